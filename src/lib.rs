@@ -25,22 +25,51 @@
 //! assert_eq!(one2, "one");
 //! ```
 //!
+//! # Sharding
+//!
+//! Internally the memo map is split into a fixed number of shards, each
+//! guarded by its own mutex.  A key is routed to its shard by hashing, so
+//! operations on different shards can proceed concurrently instead of
+//! serializing on a single lock.  The number of shards defaults to a small
+//! multiple of the available parallelism but can be picked explicitly with
+//! [`MemoMap::with_shards`].
+//!
+//! # Crate Features
+//!
+//! * `ahash`: switches the default hash builder from [`RandomState`] to
+//!   `ahash::RandomState`, which is faster for the small keys typical of
+//!   memoization workloads at the cost of HashDoS resistance.  See
+//!   [`DefaultHashBuilder`].
+//! * `serde`: implements `Serialize`/`Deserialize` for [`MemoMap`], so a
+//!   warmed cache can be persisted to disk and reloaded on startup rather
+//!   than recomputed.
+//! * `rayon`: adds [`MemoMap::par_iter`], a parallel counterpart to
+//!   [`iter`](MemoMap::iter) that visits shards concurrently.
+//!
 //! # Notes on Iteration
 //!
-//! Because the memo map internally uses a mutex it needs to be held during
-//! iteration.  This is potentially dangerous as it means you can easily
-//! deadlock yourself when trying to use the memo map while iterating.  The
-//! iteration functionality thus has to be used with great care.
+//! Iterating locks shards lazily, one at a time, as the iterator advances
+//! rather than holding a single lock over the whole map.  This still means
+//! that, while iterating, you must not use the memo map from the same thread
+//! in a way that locks the shard the iterator is currently holding (for
+//! instance inserting a key that lands in that shard), as that deadlocks;
+//! other threads touching that shard merely wait until the iterator moves
+//! on.  It is far less deadlock-prone than holding one global lock for the
+//! entire iteration.
 use std::borrow::Borrow;
-use std::collections::hash_map::{Entry, RandomState};
+use std::collections::hash_map::Entry;
+#[cfg(not(feature = "ahash"))]
+use std::collections::hash_map::RandomState;
 use std::collections::HashMap;
 use std::convert::Infallible;
 use std::hash::{BuildHasher, Hash};
 use std::mem::{transmute, ManuallyDrop};
-use std::sync::{Mutex, MutexGuard};
+use std::sync::{Arc, Mutex, MutexGuard};
 
 use stable_deref_trait::StableDeref;
 
+pub use std::collections::TryReserveError;
+
 macro_rules! lock {
     ($mutex:expr) => {
         match $mutex.lock() {
@@ -50,43 +79,149 @@ macro_rules! lock {
     };
 }
 
+/// Shards are multiplied against the available parallelism to pick a
+/// default shard count, giving threads some headroom before they start
+/// contending on the same shard.
+const DEFAULT_SHARDS_PER_CPU: usize = 4;
+
+/// Rounds `n` up to the next power of two, with a floor of `1`.
+fn shard_count(n: usize) -> usize {
+    n.max(1).next_power_of_two()
+}
+
+fn default_shard_count() -> usize {
+    let parallelism = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1);
+    shard_count(parallelism * DEFAULT_SHARDS_PER_CPU)
+}
+
+/// The hash builder [`MemoMap`] uses by default.
+///
+/// Without the `ahash` feature this is plain [`RandomState`], the same
+/// SipHash 1-3 based builder std's `HashMap` defaults to.  Enable the
+/// `ahash` feature to switch this alias to `ahash::RandomState` for
+/// faster hashing at the cost of HashDoS resistance.
+#[cfg(not(feature = "ahash"))]
+pub type DefaultHashBuilder = RandomState;
+
+/// The hash builder [`MemoMap`] uses by default.
+///
+/// See the `ahash`-disabled version of this type alias for details.
+#[cfg(feature = "ahash")]
+pub type DefaultHashBuilder = ahash::RandomState;
+
+/// Lets every shard's `HashMap` share one `BuildHasher` instance through an
+/// `Arc` instead of each shard needing its own clone of it.
+///
+/// This is what lets [`MemoMap::with_hasher`] accept hash builders that
+/// aren't [`Clone`], same as before sharding was introduced.
+struct SharedHasher<S>(Arc<S>);
+
+impl<S> Clone for SharedHasher<S> {
+    fn clone(&self) -> Self {
+        SharedHasher(self.0.clone())
+    }
+}
+
+impl<S: BuildHasher> BuildHasher for SharedHasher<S> {
+    type Hasher = S::Hasher;
+
+    fn build_hasher(&self) -> Self::Hasher {
+        self.0.build_hasher()
+    }
+}
+
+/// A single lock-striped shard of a [`MemoMap`].
+type Shard<K, V, S> = Mutex<HashMap<K, V, SharedHasher<S>>>;
+
 /// An insert only, thread safe hash map to memoize values.
 #[derive(Debug)]
-pub struct MemoMap<K, V, S = RandomState> {
-    inner: Mutex<HashMap<K, V, S>>,
+pub struct MemoMap<K, V, S = DefaultHashBuilder> {
+    shards: Box<[Shard<K, V, S>]>,
+    hash_builder: Arc<S>,
 }
 
 impl<K: Clone, V: Clone, S: Clone> Clone for MemoMap<K, V, S> {
     fn clone(&self) -> Self {
         Self {
-            inner: Mutex::new(lock!(self.inner).clone()),
+            shards: self
+                .shards
+                .iter()
+                .map(|shard| Mutex::new(lock!(shard).clone()))
+                .collect(),
+            hash_builder: self.hash_builder.clone(),
         }
     }
 }
 
 impl<K, V, S: Default> Default for MemoMap<K, V, S> {
     fn default() -> Self {
-        MemoMap {
-            inner: Mutex::new(HashMap::default()),
-        }
+        MemoMap::with_hasher(S::default())
     }
 }
 
-impl<K, V> MemoMap<K, V, RandomState> {
+impl<K, V> MemoMap<K, V, DefaultHashBuilder> {
     /// Creates an empty `MemoMap`.
-    pub fn new() -> MemoMap<K, V, RandomState> {
-        MemoMap {
-            inner: Mutex::default(),
-        }
+    pub fn new() -> MemoMap<K, V, DefaultHashBuilder> {
+        MemoMap::with_hasher(DefaultHashBuilder::default())
+    }
+
+    /// Creates an empty `MemoMap` with at least the specified capacity.
+    ///
+    /// The capacity is split evenly across the internal shards, so each
+    /// shard can hold its share of `capacity` elements without
+    /// reallocating.  Keys are not guaranteed to spread evenly, so a shard
+    /// that receives more than its share may still reallocate before the
+    /// map as a whole holds `capacity` elements.
+    pub fn with_capacity(capacity: usize) -> MemoMap<K, V, DefaultHashBuilder> {
+        MemoMap::with_capacity_and_hasher(capacity, DefaultHashBuilder::default())
     }
 }
 
 impl<K, V, S> MemoMap<K, V, S> {
     /// Creates an empty `MemoMap` which will use the given hash builder to hash
     /// keys.
+    ///
+    /// The map is split into a default number of shards derived from the
+    /// available parallelism.  Use [`with_shards`](Self::with_shards) if you
+    /// need to pick the shard count yourself.
     pub fn with_hasher(hash_builder: S) -> MemoMap<K, V, S> {
+        MemoMap::with_shards_capacity_and_hasher(default_shard_count(), 0, hash_builder)
+    }
+
+    /// Creates an empty `MemoMap` with at least the specified capacity,
+    /// which will use the given hash builder to hash keys.
+    pub fn with_capacity_and_hasher(capacity: usize, hash_builder: S) -> MemoMap<K, V, S> {
+        MemoMap::with_shards_capacity_and_hasher(default_shard_count(), capacity, hash_builder)
+    }
+
+    /// Creates an empty `MemoMap` with (at least) the given number of shards.
+    ///
+    /// `n` is rounded up to the next power of two since shard selection
+    /// relies on masking the hash instead of taking a remainder.
+    pub fn with_shards(n: usize) -> MemoMap<K, V, S>
+    where
+        S: Default,
+    {
+        MemoMap::with_shards_capacity_and_hasher(n, 0, S::default())
+    }
+
+    fn with_shards_capacity_and_hasher(n: usize, capacity: usize, hash_builder: S) -> MemoMap<K, V, S> {
+        let n = shard_count(n);
+        let per_shard = capacity.div_ceil(n);
+        let hash_builder = Arc::new(hash_builder);
+        let shards = (0..n)
+            .map(|_| {
+                Mutex::new(HashMap::with_capacity_and_hasher(
+                    per_shard,
+                    SharedHasher(hash_builder.clone()),
+                ))
+            })
+            .collect();
         MemoMap {
-            inner: Mutex::new(HashMap::with_hasher(hash_builder)),
+            shards,
+            hash_builder,
         }
     }
 }
@@ -97,6 +232,15 @@ where
     V: StableDeref,
     S: BuildHasher,
 {
+    /// Returns the index of the shard that owns `key`.
+    fn shard_index<Q>(&self, key: &Q) -> usize
+    where
+        Q: Hash + ?Sized,
+    {
+        let hash = BuildHasher::hash_one(&*self.hash_builder, key);
+        (hash as usize) & (self.shards.len() - 1)
+    }
+
     /// Inserts a value into the memo map.
     ///
     /// This inserts a value for a specific key into the memo map.  If the
@@ -105,8 +249,8 @@ where
     /// recommended to instead use [`get_or_insert`](Self::get_or_insert) or
     /// it's sibling [`get_or_try_insert`](Self::get_or_try_insert).
     pub fn insert(&self, key: K, value: V) -> bool {
-        let mut inner = lock!(self.inner);
-        match inner.entry(key) {
+        let mut shard = lock!(self.shards[self.shard_index(&key)]);
+        match shard.entry(key) {
             Entry::Occupied(_) => false,
             Entry::Vacant(vacant) => {
                 vacant.insert(value);
@@ -124,7 +268,7 @@ where
         Q: Hash + Eq + ?Sized,
         K: Borrow<Q>,
     {
-        lock!(self.inner).contains_key(key)
+        lock!(self.shards[self.shard_index(key)]).contains_key(key)
     }
 
     /// Returns a reference to the value corresponding to the key.
@@ -136,11 +280,27 @@ where
         Q: Hash + Eq + ?Sized,
         K: Borrow<Q>,
     {
-        let inner = lock!(self.inner);
-        let value = inner.get(key)?;
+        let shard = lock!(self.shards[self.shard_index(key)]);
+        let value = shard.get(key)?;
         Some(unsafe { transmute::<_, _>(value) })
     }
 
+    /// Returns the stored key and its value for the supplied key.
+    ///
+    /// Like [`get`](Self::get), but also returns the `K` that's actually
+    /// stored in the map, which may differ from the borrowed lookup key
+    /// (for example if `K` is some canonical form the key gets mapped to
+    /// on insert).
+    pub fn get_key_value<Q>(&self, key: &Q) -> Option<(&K, &V)>
+    where
+        Q: Hash + Eq + ?Sized,
+        K: Borrow<Q>,
+    {
+        let shard = lock!(self.shards[self.shard_index(key)]);
+        let (k, v) = shard.get_key_value(key)?;
+        Some(unsafe { transmute::<_, _>((k, v)) })
+    }
+
     /// Returns a reference to the value corresponding to the key or inserts.
     ///
     /// This is the preferred way to work with a memo map: if the value has not
@@ -148,6 +308,15 @@ where
     /// otherwise the already stored value is returned.  The creator function itself
     /// can be falliable and the error is passed through.
     ///
+    /// The owning shard stays locked for the duration of `creator`, so a
+    /// creator that calls back into the same map will deadlock if the key
+    /// it touches happens to land in that same shard — and proceed
+    /// perfectly fine otherwise, since other shards are unaffected.  Since
+    /// shard assignment comes from hashing the key, whether a given
+    /// recursive call deadlocks depends on hash distribution rather than
+    /// being guaranteed either way, so don't rely on it failing fast in
+    /// tests.
+    ///
     /// If the creator is infallible, [`get_or_insert`](Self::get_or_insert) can be used.
     pub fn get_or_try_insert<Q, F, E>(&self, key: &Q, creator: F) -> Result<&V, E>
     where
@@ -155,12 +324,12 @@ where
         K: Borrow<Q>,
         F: FnOnce() -> Result<V, E>,
     {
-        let mut inner = lock!(self.inner);
-        let value = if let Some(value) = inner.get(key) {
+        let mut shard = lock!(self.shards[self.shard_index(key)]);
+        let value = if let Some(value) = shard.get(key) {
             value
         } else {
-            inner.insert(key.to_owned(), creator()?);
-            inner.get(key).unwrap()
+            shard.insert(key.to_owned(), creator()?);
+            shard.get(key).unwrap()
         };
         Ok(unsafe { transmute::<_, _>(value) })
     }
@@ -197,6 +366,42 @@ where
             .unwrap()
     }
 
+    /// Returns a reference to the value corresponding to the key or inserts.
+    ///
+    /// Like [`get_or_insert`](Self::get_or_insert), but the creator function
+    /// receives the borrowed key, so it doesn't have to capture its own copy
+    /// separately when the value is derived from it.
+    ///
+    /// The owning shard stays locked for the duration of `creator`, so a
+    /// creator that calls back into the same map will deadlock if the key
+    /// it touches happens to land in that same shard — see
+    /// [`get_or_try_insert`](Self::get_or_try_insert) for more on this.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use memo_map::MemoMap;
+    /// let memo = MemoMap::new();
+    /// let value = memo.get_or_insert_with_key("key", |key| key.to_uppercase());
+    /// assert_eq!(value, "KEY");
+    /// ```
+    pub fn get_or_insert_with_key<Q, F>(&self, key: &Q, creator: F) -> &V
+    where
+        Q: Hash + Eq + ToOwned<Owned = K> + ?Sized,
+        K: Borrow<Q>,
+        F: FnOnce(&Q) -> V,
+    {
+        let mut shard = lock!(self.shards[self.shard_index(key)]);
+        let value = if let Some(value) = shard.get(key) {
+            value
+        } else {
+            let value = creator(key);
+            shard.insert(key.to_owned(), value);
+            shard.get(key).unwrap()
+        };
+        unsafe { transmute::<_, _>(value) }
+    }
+
     /// Returns the number of items in the map.
     ///
     /// # Example
@@ -212,25 +417,47 @@ where
     /// assert_eq!(memo.len(), 2);
     /// ```
     pub fn len(&self) -> usize {
-        lock!(self.inner).len()
+        self.shards.iter().map(|shard| lock!(shard).len()).sum()
     }
 
     /// Returns `true` if the memo map contains no items.
     pub fn is_empty(&self) -> bool {
-        lock!(self.inner).is_empty()
+        self.shards.iter().all(|shard| lock!(shard).is_empty())
+    }
+
+    /// Reserves capacity for at least `additional` more elements.
+    ///
+    /// The hint is spread evenly across the internal shards, like
+    /// [`HashMap::reserve`].
+    pub fn reserve(&self, additional: usize) {
+        let per_shard = additional.div_ceil(self.shards.len());
+        for shard in self.shards.iter() {
+            lock!(shard).reserve(per_shard);
+        }
+    }
+
+    /// Fallible version of [`reserve`](Self::reserve).
+    ///
+    /// Returns an error instead of panicking or aborting if the capacity
+    /// overflows or the allocator reports a failure on any shard.
+    pub fn try_reserve(&self, additional: usize) -> Result<(), TryReserveError> {
+        let per_shard = additional.div_ceil(self.shards.len());
+        for shard in self.shards.iter() {
+            lock!(shard).try_reserve(per_shard)?;
+        }
+        Ok(())
     }
 
     /// An iterator visiting all key-value pairs in arbitrary order. The
     /// iterator element type is `(&'a K, &'a V)`.
     ///
-    /// Important note: during iteration the map is locked!  This means that you
-    /// must not perform modifications to the map or you will run into deadlocks.
+    /// The iterator locks shards lazily, one at a time, as it advances; it
+    /// never holds more than one shard's lock at once.
     pub fn iter(&self) -> Iter<'_, K, V, S> {
-        let guard = lock!(self.inner);
-        let iter = guard.iter();
         Iter {
-            iter: unsafe { transmute::<_, _>(iter) },
-            guard: ManuallyDrop::new(guard),
+            shards: &self.shards,
+            next_shard: 0,
+            current: None,
         }
     }
 
@@ -239,18 +466,45 @@ where
     pub fn keys(&self) -> Keys<'_, K, V, S> {
         Keys { iter: self.iter() }
     }
+
+    /// A parallel iterator visiting all key-value pairs in arbitrary order.
+    ///
+    /// Each shard is handed to a separate rayon task, so the shards are
+    /// traversed concurrently; within a shard entries are still visited
+    /// sequentially.
+    #[cfg(feature = "rayon")]
+    pub fn par_iter(&self) -> impl rayon::iter::ParallelIterator<Item = (&K, &V)>
+    where
+        K: Sync + Send,
+        V: Sync + Send,
+        S: Sync + Send,
+    {
+        use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+
+        self.shards
+            .par_iter()
+            .flat_map_iter(ShardIter::<'_, K, V, SharedHasher<S>>::new)
+    }
 }
 
-/// An iterator over the items of a [`MemoMap`].
-///
-/// This struct is created by the [`iter`](MemoMap::iter) method on [`MemoMap`].
-/// See its documentation for more information.
-pub struct Iter<'a, K, V, S> {
+/// Holds the lock for a single shard while iterating over its entries.
+struct ShardIter<'a, K, V, S> {
     guard: ManuallyDrop<MutexGuard<'a, HashMap<K, V, S>>>,
     iter: std::collections::hash_map::Iter<'a, K, V>,
 }
 
-impl<'a, K, V, S> Drop for Iter<'a, K, V, S> {
+impl<'a, K, V, S> ShardIter<'a, K, V, S> {
+    fn new(shard: &'a Mutex<HashMap<K, V, S>>) -> Self {
+        let guard = lock!(shard);
+        let iter = unsafe { transmute::<_, _>(guard.iter()) };
+        ShardIter {
+            guard: ManuallyDrop::new(guard),
+            iter,
+        }
+    }
+}
+
+impl<'a, K, V, S> Drop for ShardIter<'a, K, V, S> {
     fn drop(&mut self) {
         unsafe {
             ManuallyDrop::drop(&mut self.guard);
@@ -258,11 +512,39 @@ impl<'a, K, V, S> Drop for Iter<'a, K, V, S> {
     }
 }
 
+impl<'a, K, V, S> Iterator for ShardIter<'a, K, V, S> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next()
+    }
+}
+
+/// An iterator over the items of a [`MemoMap`].
+///
+/// This struct is created by the [`iter`](MemoMap::iter) method on [`MemoMap`].
+/// See its documentation for more information.
+pub struct Iter<'a, K, V, S> {
+    shards: &'a [Shard<K, V, S>],
+    next_shard: usize,
+    current: Option<ShardIter<'a, K, V, SharedHasher<S>>>,
+}
+
 impl<'a, K, V, S> Iterator for Iter<'a, K, V, S> {
     type Item = (&'a K, &'a V);
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.iter.next().map(|(k, v)| (k, v))
+        loop {
+            if let Some(shard_iter) = &mut self.current {
+                if let Some(item) = shard_iter.next() {
+                    return Some(item);
+                }
+                self.current = None;
+            }
+            let shard = self.shards.get(self.next_shard)?;
+            self.next_shard += 1;
+            self.current = Some(ShardIter::new(shard));
+        }
     }
 }
 
@@ -282,6 +564,51 @@ impl<'a, K, V, S> Iterator for Keys<'a, K, V, S> {
     }
 }
 
+#[cfg(feature = "serde")]
+impl<K, V, S> serde::Serialize for MemoMap<K, V, S>
+where
+    K: Eq + Hash + serde::Serialize,
+    V: StableDeref + serde::Serialize,
+    S: BuildHasher,
+{
+    fn serialize<Ser>(&self, serializer: Ser) -> Result<Ser::Ok, Ser::Error>
+    where
+        Ser: serde::Serializer,
+    {
+        use serde::ser::SerializeMap;
+
+        let mut map = serializer.serialize_map(Some(self.len()))?;
+        for (k, v) in self.iter() {
+            map.serialize_entry(k, v)?;
+        }
+        map.end()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, K, V, S> serde::Deserialize<'de> for MemoMap<K, V, S>
+where
+    K: Eq + Hash + serde::Deserialize<'de>,
+    V: StableDeref + serde::Deserialize<'de>,
+    S: BuildHasher + Default,
+{
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        // Deserializing builds a fresh map, so we can bypass the insert-only
+        // rule entirely: decode into a plain `HashMap` first, then spread its
+        // entries across the right shards.
+        let inner = <HashMap<K, V, S> as serde::Deserialize>::deserialize(deserializer)?;
+        let memo = MemoMap::with_capacity_and_hasher(inner.len(), S::default());
+        for (key, value) in inner {
+            let idx = memo.shard_index(&key);
+            lock!(memo.shards[idx]).insert(key, value);
+        }
+        Ok(memo)
+    }
+}
+
 #[test]
 fn test_insert() {
     let memo = MemoMap::new();
@@ -312,10 +639,113 @@ fn test_keys() {
     assert_eq!(values, vec![1, 2, 3]);
 }
 
+#[test]
+fn test_get_key_value() {
+    let memo = MemoMap::new();
+    memo.insert(1, "one");
+    assert_eq!(memo.get_key_value(&1), Some((&1, &"one")));
+    assert_eq!(memo.get_key_value(&2), None);
+}
+
+#[test]
+fn test_get_or_insert_with_key() {
+    let memo = MemoMap::new();
+    let value = memo.get_or_insert_with_key("key", |key| key.to_uppercase());
+    assert_eq!(value, "KEY");
+    let value = memo.get_or_insert_with_key("key", |key| key.to_lowercase());
+    assert_eq!(value, "KEY");
+}
+
 #[test]
 fn test_contains() {
     let memo = MemoMap::new();
     memo.insert(1, "one");
     assert!(memo.contains_key(&1));
     assert!(!memo.contains_key(&2));
-}
\ No newline at end of file
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serde_roundtrip() {
+    let memo = MemoMap::new();
+    memo.insert(1u32, Box::new("one".to_string()));
+    memo.insert(2u32, Box::new("two".to_string()));
+
+    let json = serde_json::to_string(&memo).unwrap();
+    let restored: MemoMap<u32, Box<String>> = serde_json::from_str(&json).unwrap();
+    assert_eq!(restored.get(&1).map(|v| v.as_str()), Some("one"));
+    assert_eq!(restored.get(&2).map(|v| v.as_str()), Some("two"));
+    assert_eq!(restored.len(), 2);
+}
+
+#[test]
+fn test_with_capacity() {
+    let memo: MemoMap<u32, Box<u32>> = MemoMap::with_capacity(100);
+    memo.insert(1, Box::new(1));
+    assert_eq!(memo.get(&1).cloned(), Some(Box::new(1)));
+}
+
+#[test]
+fn test_reserve() {
+    let memo: MemoMap<u32, Box<u32>> = MemoMap::new();
+    memo.reserve(100);
+    memo.try_reserve(100).unwrap();
+    memo.insert(1, Box::new(1));
+    assert_eq!(memo.get(&1).cloned(), Some(Box::new(1)));
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn test_par_iter() {
+    use rayon::iter::ParallelIterator;
+
+    let memo = MemoMap::new();
+    memo.insert(1, "one");
+    memo.insert(2, "two");
+    memo.insert(3, "three");
+    let mut values = memo.par_iter().map(|(k, v)| (*k, *v)).collect::<Vec<_>>();
+    values.sort();
+    assert_eq!(values, vec![(1, "one"), (2, "two"), (3, "three")]);
+}
+
+#[test]
+fn test_with_shards() {
+    let memo: MemoMap<u32, Box<u32>> = MemoMap::with_shards(7);
+    assert_eq!(memo.shards.len(), 8);
+    memo.insert(1, Box::new(1));
+    memo.insert(2, Box::new(2));
+    assert_eq!(memo.get(&1).cloned(), Some(Box::new(1)));
+}
+
+#[test]
+fn test_iteration_does_not_block_other_shards() {
+    use std::sync::mpsc;
+    use std::time::{Duration, Instant};
+
+    let memo: MemoMap<u32, Box<u32>> = MemoMap::with_shards(2);
+    let key_in_shard_0 = (0u32..).find(|k| memo.shard_index(k) == 0).unwrap();
+    let key_in_shard_1 = (0u32..).find(|k| memo.shard_index(k) == 1).unwrap();
+    memo.insert(key_in_shard_0, Box::new(0));
+
+    let (ready_tx, ready_rx) = mpsc::channel();
+    std::thread::scope(|scope| {
+        scope.spawn(|| {
+            // Start walking shard 0 and keep its lock held for a while.
+            let mut iter = memo.iter();
+            assert!(iter.next().is_some());
+            ready_tx.send(()).unwrap();
+            std::thread::sleep(Duration::from_millis(200));
+            drop(iter);
+        });
+
+        ready_rx.recv().unwrap();
+        // Inserting into the other shard must not have to wait for shard
+        // 0's iterator to finish.
+        let start = Instant::now();
+        memo.insert(key_in_shard_1, Box::new(1));
+        assert!(start.elapsed() < Duration::from_millis(100));
+    });
+
+    assert_eq!(memo.get(&key_in_shard_0).cloned(), Some(Box::new(0)));
+    assert_eq!(memo.get(&key_in_shard_1).cloned(), Some(Box::new(1)));
+}